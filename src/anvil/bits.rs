@@ -1,16 +1,51 @@
 use bit_field::BitArray;
 
+/// The number of blocks in a chunk section, arranged 16x16x16.
+const BLOCKS_PER_SECTION: usize = 16 * 16 * 16;
+
+/// Which bit-packing layout a blockstates long array uses.
+///
+/// Minecraft changed how it packs palette indices into the `BlockStates` long array in 1.16. Callers need
+/// to know which world version produced the data in order to unpack it correctly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStateVersion {
+    /// Used up to and including 1.15. Values are packed as tightly as possible and can bleed across `i64`
+    /// boundaries.
+    Pre116,
+
+    /// Used from 1.16 onwards. Each long holds as many whole values as fit; any leftover bits at the top of
+    /// a long are left unused rather than spilling into the next long.
+    Post116,
+}
+
 /// Expand blockstate data so each block is an element of a `Vec`.
 ///
 /// This requires the number of items in the palette of the section the blockstates came from. This is because
 /// blockstate is packed with as few bits as possible. If the maximum index in the palette fits in 5 bits, then
-/// every 5 bits of the blockstates will represent a block. Blocks bleed into one another, so remainder bits
-/// are tracked and handled for you.
+/// every 5 bits of the blockstates will represent a block.
 ///
-/// This works for Minecraft 1.15. This format due to change in 1.16 so that bits do not bleed into other longs.
-/// This function will not work for 1.16 blockstates yet.
-pub fn expand_blockstates(state: &[i64], palette_len: usize) -> Vec<u16> {
-    expand_generic(state, bits_per_block(palette_len))
+/// The `version` determines whether blocks are allowed to bleed into one another (pre-1.16) or whether each
+/// long is padded out so that blocks never span two longs (1.16 onwards).
+pub fn expand_blockstates(
+    state: &[i64],
+    palette_len: usize,
+    version: BlockStateVersion,
+) -> Vec<u16> {
+    expand_blockstates_iter(state, palette_len, version).collect()
+}
+
+/// Lazily iterate the blocks in `state`, same as `expand_blockstates` but without allocating a `Vec`.
+pub fn expand_blockstates_iter(
+    state: &[i64],
+    palette_len: usize,
+    version: BlockStateVersion,
+) -> BitsIter<'_> {
+    let bits = bits_per_block(palette_len);
+
+    match version {
+        BlockStateVersion::Pre116 => expand_generic_iter(state, bits),
+        BlockStateVersion::Post116 => expand_generic_packed_iter(state, bits, BLOCKS_PER_SECTION),
+    }
 }
 
 /// Expand heightmap data. This is equivalent to `expand_generic(data, 9)`.
@@ -18,23 +53,182 @@ pub fn expand_heightmap(data: &[i64]) -> Vec<u16> {
     expand_generic(data, 9)
 }
 
-/// Expand data into individual items. Currently a copy of data is made here to convert to unsigned integers
-/// to make bit operations more tractable.
+/// Expand data into individual items using the pre-1.16 layout, where items can bleed across `i64`
+/// boundaries.
 pub fn expand_generic(data: &[i64], bits_per_item: usize) -> Vec<u16> {
-    let bits = bits_per_item;
-    let mut result: Vec<u16> = vec![0; (data.len() * 64) / bits];
+    expand_generic_iter(data, bits_per_item).collect()
+}
+
+/// Lazily iterate the items packed into `data` using the pre-1.16, bit-bleeding layout, without allocating
+/// a `Vec` for the result or making a copy to treat `data` as unsigned.
+pub fn expand_generic_iter(data: &[i64], bits_per_item: usize) -> BitsIter<'_> {
+    BitsIter::bleeding(data, bits_per_item)
+}
+
+/// Lazily iterate the items packed into `data` using the 1.16+, non-bleeding layout, without allocating a
+/// `Vec` for the result or making a copy to treat `data` as unsigned.
+pub fn expand_generic_packed_iter(
+    data: &[i64],
+    bits_per_item: usize,
+    count: usize,
+) -> BitsIter<'_> {
+    BitsIter::packed(data, bits_per_item, count)
+}
+
+/// Which of the two long-array bit-packing layouts a `BitsIter` is reading.
+enum BitsLayout {
+    /// Pre-1.16: items are packed as tightly as possible and can bleed across `i64` boundaries.
+    Bleeding,
+
+    /// 1.16+: each long holds as many whole items as fit, with the top bits left as unused padding.
+    Packed { values_per_long: usize, mask: u64 },
+}
+
+/// A lazy, allocation-free iterator over the items packed into a long array, supporting both the pre-1.16
+/// bleeding layout and the 1.16+ non-bleeding layout.
+///
+/// Reinterpreting the `&[i64]` as `&[u64]` is a pure bit-cast (same size and alignment), so this does not
+/// need to copy the data to treat it as unsigned.
+pub struct BitsIter<'a> {
+    words: &'a [u64],
+    bits_per_item: usize,
+    layout: BitsLayout,
+    index: usize,
+    len: usize,
+}
+
+impl<'a> BitsIter<'a> {
+    fn bleeding(data: &'a [i64], bits_per_item: usize) -> Self {
+        let len = (data.len() * 64) / bits_per_item;
+
+        Self {
+            words: bitcast(data),
+            bits_per_item,
+            layout: BitsLayout::Bleeding,
+            index: 0,
+            len,
+        }
+    }
+
+    fn packed(data: &'a [i64], bits_per_item: usize, count: usize) -> Self {
+        let values_per_long = 64 / bits_per_item;
+        let mask = (1u64 << bits_per_item) - 1;
+
+        Self {
+            words: bitcast(data),
+            bits_per_item,
+            layout: BitsLayout::Packed {
+                values_per_long,
+                mask,
+            },
+            index: 0,
+            len: count,
+        }
+    }
+}
 
-    // Unfortunely make a copy here in order to treat the data as u64 rather than i64.
-    // At some point we will change the parser to let us take the data as u64 rather than i64.
-    let copy: Vec<_> = data.iter().map(|i| *i as u64).collect();
+impl<'a> Iterator for BitsIter<'a> {
+    type Item = u16;
 
-    for i in 0..result.len() {
+    fn next(&mut self) -> Option<u16> {
+        if self.index >= self.len {
+            return None;
+        }
+
+        let value = match self.layout {
+            BitsLayout::Bleeding => {
+                let begin = self.index * self.bits_per_item;
+                let end = begin + self.bits_per_item;
+                self.words.get_bits(begin..end) as u16
+            }
+            BitsLayout::Packed {
+                values_per_long,
+                mask,
+            } => {
+                let long = self.words[self.index / values_per_long];
+                let shift = (self.index % values_per_long) * self.bits_per_item;
+                ((long >> shift) & mask) as u16
+            }
+        };
+        self.index += 1;
+
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for BitsIter<'a> {}
+
+/// Reinterpret `data` as `&[u64]`. Safe: `i64` and `u64` have identical size and alignment, and the result
+/// is only ever read through, never written through.
+fn bitcast(data: &[i64]) -> &[u64] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u64, data.len()) }
+}
+
+/// Expand data into individual items using the 1.16+ layout, where each long only ever holds whole items:
+/// with `vpl = 64 / bits_per_item` values per long, the top `64 - vpl * bits_per_item` bits of every long
+/// are unused padding rather than the start of the next item.
+///
+/// Unlike `expand_generic`, the padding bits don't encode real items, so the number of items can't be
+/// derived from `data.len()` alone. `count` must be passed explicitly, e.g. `16 * 16 * 16` for a section's
+/// blockstates.
+pub fn expand_generic_packed(data: &[i64], bits_per_item: usize, count: usize) -> Vec<u16> {
+    expand_generic_packed_iter(data, bits_per_item, count).collect()
+}
+
+/// Pack blockstate data back down into the long array format it was read from. This is the exact inverse
+/// of `expand_blockstates`: `pack_blockstates(&expand_blockstates(state, palette_len, version), palette_len,
+/// version) == state`.
+pub fn pack_blockstates(items: &[u16], palette_len: usize, version: BlockStateVersion) -> Vec<i64> {
+    let bits = bits_per_block(palette_len);
+
+    match version {
+        BlockStateVersion::Pre116 => pack_generic(items, bits),
+        BlockStateVersion::Post116 => pack_generic_packed(items, bits),
+    }
+}
+
+/// Pack heightmap data back down into the long array format it was read from. This is equivalent to
+/// `pack_generic(items, 9)`, and is the exact inverse of `expand_heightmap`.
+pub fn pack_heightmap(items: &[u16]) -> Vec<i64> {
+    pack_generic(items, 9)
+}
+
+/// Pack items into the pre-1.16 layout, where items can bleed across `i64` boundaries. This is the exact
+/// inverse of `expand_generic`.
+pub fn pack_generic(items: &[u16], bits_per_item: usize) -> Vec<i64> {
+    let bits = bits_per_item;
+    let longs = (items.len() * bits).div_ceil(64);
+    let mut result: Vec<u64> = vec![0; longs];
+
+    for (i, item) in items.iter().enumerate() {
         let begin = i * bits;
         let end = begin + bits;
-        result[i] = copy.get_bits(begin..end) as u16;
+        result.set_bits(begin..end, *item as u64);
     }
 
-    result
+    result.into_iter().map(|i| i as i64).collect()
+}
+
+/// Pack items into the 1.16+ layout, where each long only ever holds whole items and any leftover bits at
+/// the top of a long are left as padding. This is the exact inverse of `expand_generic_packed`.
+pub fn pack_generic_packed(items: &[u16], bits_per_item: usize) -> Vec<i64> {
+    let bits = bits_per_item;
+    let values_per_long = 64 / bits;
+    let mask = (1u64 << bits) - 1;
+    let longs = items.len().div_ceil(values_per_long);
+    let mut result: Vec<u64> = vec![0; longs];
+
+    for (i, item) in items.iter().enumerate() {
+        let shift = (i % values_per_long) * bits;
+        result[i / values_per_long] |= (*item as u64 & mask) << shift;
+    }
+
+    result.into_iter().map(|i| i as i64).collect()
 }
 
 /// Get the number of bits that will be used in `Blockstates` per block.
@@ -48,6 +242,82 @@ pub fn bits_per_block(palette_len: usize) -> usize {
     }
 }
 
+/// The number of biomes in a chunk section's biome grid, arranged 4x4x4.
+const BIOMES_PER_SECTION: usize = 4 * 4 * 4;
+
+/// Get the number of bits that will be used in the 1.18+ biome paletted container per biome.
+///
+/// Unlike `bits_per_block`, there is no 4-bit floor here: a single-entry palette is stored using 1 bit per
+/// biome rather than being rounded up to 4.
+pub fn bits_per_biome(palette_len: usize) -> usize {
+    std::cmp::max((palette_len as f64).log2().ceil() as usize, 1)
+}
+
+/// Expand biome data so each biome is an element of a `Vec`.
+///
+/// This unpacks the 4x4x4 biome grid introduced in 1.18 using the 1.16+, non-bleeding long array layout.
+/// Biome paletted containers need their own bit-width rule, see `bits_per_biome`.
+pub fn expand_biomes(data: &[i64], palette_len: usize) -> Vec<u16> {
+    expand_biomes_iter(data, palette_len).collect()
+}
+
+/// Lazily iterate the biomes in `data`, same as `expand_biomes` but without allocating a `Vec`.
+pub fn expand_biomes_iter(data: &[i64], palette_len: usize) -> BitsIter<'_> {
+    expand_generic_packed_iter(data, bits_per_biome(palette_len), BIOMES_PER_SECTION)
+}
+
+/// A single entry in a chunk section's block palette, as stored under the `Palette` list tag of a
+/// `BlockStates` compound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// The block's resource name, e.g. `minecraft:stone`.
+    pub name: String,
+}
+
+/// A chunk section's block data: expanded blockstate indices (if present) paired with the palette they
+/// index into.
+///
+/// Every consumer of `expand_blockstates` otherwise has to re-derive the `(x, y, z) -> long-array index ->
+/// palette entry` lookup itself; `Section` does this once, correctly, including the special case where a
+/// section's palette has a single entry. In that case Minecraft omits the `BlockStates` array entirely
+/// since every block is trivially `palette[0]`.
+pub struct Section<'a> {
+    indices: Option<&'a [u16]>,
+    palette: &'a [Palette],
+}
+
+impl<'a> Section<'a> {
+    /// Build a section from expanded blockstate indices and the palette they index into.
+    ///
+    /// `indices` should be `None` when the section has no `BlockStates` array, i.e. its palette has a
+    /// single entry and every block is `palette[0]`.
+    pub fn new(indices: Option<&'a [u16]>, palette: &'a [Palette]) -> Self {
+        Self { indices, palette }
+    }
+
+    /// Get the palette entry for the block at `(x, y, z)`, each in `0..16`.
+    ///
+    /// Blocks are addressed in YZX order, the order Minecraft stores blockstates in:
+    /// `index = (y * 16 + z) * 16 + x`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x`, `y`, or `z` is outside `0..16`.
+    pub fn block_at(&self, x: usize, y: usize, z: usize) -> &'a Palette {
+        assert!(x < 16, "x out of bounds: {}", x);
+        assert!(y < 16, "y out of bounds: {}", y);
+        assert!(z < 16, "z out of bounds: {}", z);
+
+        match self.indices {
+            Some(indices) => {
+                let index = (y * 16 + z) * 16 + x;
+                &self.palette[indices[index] as usize]
+            }
+            None => &self.palette[0],
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,6 +429,186 @@ mod tests {
         );
     }
 
+    #[test]
+    fn expand_blockstates_dispatches_on_version() {
+        let palette_len = 20; // bits_per_block(20) == 5, a realistic paletted section.
+        assert_eq!(5, bits_per_block(palette_len));
+
+        let indices: Vec<u16> = (0..BLOCKS_PER_SECTION as u16)
+            .map(|i| i % palette_len as u16)
+            .collect();
+
+        let bleeding_data = pack_generic(&indices, 5);
+        assert_eq!(
+            indices,
+            expand_blockstates(&bleeding_data, palette_len, BlockStateVersion::Pre116)
+        );
+
+        let packed_data = pack_generic_packed(&indices, 5);
+        assert_eq!(
+            indices,
+            expand_blockstates(&packed_data, palette_len, BlockStateVersion::Post116)
+        );
+    }
+
+    #[test]
+    fn expand_generic_iter_matches_expand_generic() {
+        let input: Vec<i64> = vec![
+            1299610109330100808,
+            649787462479005732,
+            329397330866873490,
+            -9060925171218247159,
+        ];
+
+        let expected = expand_generic(&input, 9);
+        let iter = expand_generic_iter(&input, 9);
+
+        assert_eq!(expected.len(), iter.len());
+        assert_eq!(expected, iter.collect::<Vec<u16>>());
+    }
+
+    /// Two longs packing the values `0..=12` at 5 bits per value: 12 values (0..=11) fill the first long's
+    /// bottom 60 bits, leaving 4 padding bits, and the 13th value (12) starts the second long.
+    fn packed_5bit_fixture() -> [i64; 2] {
+        let mut first: u64 = 0;
+        for i in 0..12u64 {
+            first |= i << (i * 5);
+        }
+        let second: u64 = 12;
+
+        [first as i64, second as i64]
+    }
+
+    #[test]
+    fn expand_generic_packed_iter_matches_expand_generic_packed() {
+        let data = packed_5bit_fixture();
+        let expected = expand_generic_packed(&data, 5, 13);
+        let iter = expand_generic_packed_iter(&data, 5, 13);
+
+        assert_eq!(expected.len(), iter.len());
+        assert_eq!(expected, iter.collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn pack_blockstates_round_trips_expand_blockstates() {
+        let palette_len = 20; // bits_per_block(20) == 5, a realistic paletted section.
+
+        let indices: Vec<u16> = (0..BLOCKS_PER_SECTION as u16)
+            .map(|i| i % palette_len as u16)
+            .collect();
+
+        for version in [BlockStateVersion::Pre116, BlockStateVersion::Post116] {
+            let packed = pack_blockstates(&indices, palette_len, version);
+            assert_eq!(indices, expand_blockstates(&packed, palette_len, version));
+        }
+    }
+
+    #[test]
+    fn pack_nether_heightmap_v1_15_2_round_trips() {
+        let input: Vec<i64> = vec![
+            2310355422147575936,
+            1155177711073787968,
+            577588855536893984,
+            288794427768446992,
+            144397213884223496,
+            72198606942111748,
+            36099303471055874,
+            -9205322385119247871,
+            4620710844295151872,
+            2310355422147575936,
+            1155177711073787968,
+            577588855536893984,
+            288794427768446992,
+            144397213884223496,
+            72198606942111748,
+            36099303471055874,
+            -9205322385119247871,
+            4620710844295151872,
+            2310355422147575936,
+            1155177711073787968,
+            577588855536893984,
+            288794427768446992,
+            144397213884223496,
+            72198606942111748,
+            36099303471055874,
+            -9205322385119247871,
+            4620710844295151872,
+            2310355422147575936,
+            1155177711073787968,
+            577588855536893984,
+            288794427768446992,
+            144397213884223496,
+            72198606942111748,
+            36099303471055874,
+            -9205322385119247871,
+            4620710844295151872,
+        ];
+
+        let expanded = expand_heightmap(&input[..]);
+        assert_eq!(input, pack_heightmap(&expanded));
+    }
+
+    #[test]
+    fn pack_heightmap_overworld_v1_15_2_round_trips() {
+        let input: Vec<i64> = vec![
+            1299610109330100808,
+            649787462479005732,
+            329397330866873490,
+            -9060925171218247159,
+            4692909455540619556,
+            2346453626107004050,
+            -8050144124289646015,
+            5198158688002654496,
+            2599149849916022926,
+            -7941846763497811896,
+            649769835865982755,
+            -1985452877601561582,
+            8230641191400739272,
+            4692909451237263588,
+            2057661397361812594,
+            -7906029485971705287,
+            5126101092889936160,
+            2599079343463931022,
+            -7941846763497811896,
+            -3970923381816146141,
+            -6606172535203224687,
+            8230641191400739144,
+            2960142884643391716,
+            2057660297841779794,
+            -8483335214034816455,
+            5126100816936184084,
+            2526951243511307406,
+            -7941882016858338234,
+            -8591634191684319453,
+            -4295817113055649007,
+            7075463488933695880,
+            3537731740163475652,
+            1768865870081737826,
+            -8338939101813906895,
+            5053902485947822360,
+            2526951242973911180,
+        ];
+
+        let expanded = expand_heightmap(&input[..]);
+        assert_eq!(input, pack_heightmap(&expanded));
+    }
+
+    #[test]
+    fn pack_generic_packed_round_trips_expand_generic_packed() {
+        let data = packed_5bit_fixture();
+        let expanded = expand_generic_packed(&data, 5, 13);
+
+        assert_eq!(data, pack_generic_packed(&expanded, 5).as_slice());
+    }
+
+    #[test]
+    fn expand_generic_packed_leaves_padding_unused() {
+        let data = packed_5bit_fixture();
+        let actual = expand_generic_packed(&data, 5, 13);
+
+        assert_eq!((0..13).collect::<Vec<u16>>(), actual);
+    }
+
     #[test]
     fn size_one_palette_still_requires_one_bit() {
         // With a palette size of 1, we don't really need to store the
@@ -182,4 +632,72 @@ mod tests {
         assert_eq!(4, bits_per_block(16));
         assert_eq!(10, bits_per_block(1 << 10));
     }
+
+    #[test]
+    fn size_one_biome_palette_requires_one_bit() {
+        // Unlike blockstates, biomes have no 4-bit floor, so a single-entry palette is still stored as one
+        // bit per biome.
+        assert_eq!(1, bits_per_biome(1));
+    }
+
+    #[test]
+    fn biome_palette_size_checks() {
+        assert_eq!(1, bits_per_biome(2));
+        assert_eq!(2, bits_per_biome(3));
+        assert_eq!(2, bits_per_biome(4));
+        assert_eq!(3, bits_per_biome(5));
+        assert_eq!(3, bits_per_biome(8));
+        assert_eq!(4, bits_per_biome(9));
+        assert_eq!(4, bits_per_biome(16));
+        assert_eq!(10, bits_per_biome(1 << 10));
+    }
+
+    #[test]
+    fn section_block_at_uses_yzx_ordering() {
+        let palette = vec![
+            Palette {
+                name: "minecraft:air".to_string(),
+            },
+            Palette {
+                name: "minecraft:stone".to_string(),
+            },
+        ];
+
+        // Only the block at (1, 0, 0) is stone; everything else is air. With YZX ordering that's index 1.
+        let mut indices = vec![0u16; 16 * 16 * 16];
+        indices[1] = 1;
+
+        let section = Section::new(Some(&indices), &palette);
+
+        assert_eq!("minecraft:stone", section.block_at(1, 0, 0).name);
+        assert_eq!("minecraft:air", section.block_at(0, 0, 0).name);
+        assert_eq!("minecraft:air", section.block_at(1, 1, 0).name);
+    }
+
+    #[test]
+    fn section_block_at_handles_single_entry_palette() {
+        let palette = vec![Palette {
+            name: "minecraft:stone".to_string(),
+        }];
+
+        let section = Section::new(None, &palette);
+
+        assert_eq!("minecraft:stone", section.block_at(0, 0, 0).name);
+        assert_eq!("minecraft:stone", section.block_at(15, 15, 15).name);
+    }
+
+    #[test]
+    fn expand_biomes_reads_4x4x4_grid() {
+        // bits_per_biome(2) == 1, so all 64 biomes (4x4x4) exactly fill a single long.
+        let mut long: u64 = 0;
+        for i in 0..BIOMES_PER_SECTION as u64 {
+            long |= (i % 2) << i;
+        }
+
+        let data = [long as i64];
+        let actual = expand_biomes(&data, 2);
+
+        let expected: Vec<u16> = (0..BIOMES_PER_SECTION as u16).map(|i| i % 2).collect();
+        assert_eq!(expected, actual);
+    }
 }